@@ -1,9 +1,20 @@
+#[cfg(feature = "serde")]
+extern crate serde;
+
 use std::fmt;
 use std::mem;
 
 const DOUBLE_MAX_TAG: u32 = 0x1FFF0;
 const SHIFTED_DOUBLE_MAX_TAG: u64 = ((DOUBLE_MAX_TAG as u64) << 47) | 0xFFFFFFFF;
 
+// `pack_nan_box` ORs the tag in starting at bit 47 (`DOUBLE_MAX_TAG`'s low
+// nibble is zero, so the tag's own low bits land exactly on bit 47 upward),
+// which means bit 47 belongs to the tag, not the payload. A payload mask of
+// `(1 << 48) - 1` therefore leaves the tag's lowest bit mixed into whatever
+// comes back out of `unpack_nan_box`, corrupting odd-tagged pointer payloads.
+// 47 bits (0..=46) is the actual safe payload width.
+const PAYLOAD_MASK: u64 = (1 << 47) - 1;
+
 #[derive(Copy, Clone, Eq, PartialEq, Ord, PartialOrd)]
 pub struct NanBox(u64);
 
@@ -12,7 +23,7 @@ impl fmt::Debug for NanBox {
         write!(f,
                "NanBox {{ tag: {:?}, payload: {:?} }}",
                self.tag(),
-               self.0 & ((1 << 48) - 1))
+               self.0 & PAYLOAD_MASK)
     }
 }
 
@@ -31,8 +42,7 @@ pub trait NanBoxable: Sized {
     }
 
     unsafe fn unpack_nan_box(value: NanBox) -> Self {
-        let mask = (1 << 48) - 1;
-        let b = NanBox(value.0 & mask);
+        let b = NanBox(value.0 & PAYLOAD_MASK);
         Self::from_nan_box(b)
     }
 }
@@ -83,7 +93,7 @@ macro_rules! impl_cast_t {
             }
 
             fn into_nan_box(self) -> NanBox {
-                debug_assert!((self as u64) >> 48 == 0);
+                debug_assert!((self as u64) & !PAYLOAD_MASK == 0);
                 NanBox(self as u64)
             }
         }
@@ -93,6 +103,236 @@ macro_rules! impl_cast_t {
 
 impl_cast_t! { T, *mut T *const T }
 
+// A naive spill flag stashed in the *top* payload bit (bit 46) is not safe:
+// that bit is part of the 47-bit payload every pointer impl above already
+// claims in full, and real heap addresses on a normal Linux x86-64 process
+// routinely have bit 46 set (the user address space fills all 47 low bits),
+// so `Box`'d spill pointers would collide with the flag almost every time.
+// Instead we steal the *lowest* payload bit: `Box<i64>`/`Box<u64>` are at
+// least 8-byte aligned, so a freshly allocated pointer's low bit is always
+// zero and free for us to use as the spill flag, while inline values are
+// shifted left by one to make room for it, leaving 46 usable inline bits
+// either way.
+const SPILL_BIT: u64 = 1;
+const INLINE_I64_MIN: i64 = -(1i64 << 45);
+const INLINE_I64_MAX: i64 = (1i64 << 45) - 1;
+const INLINE_U64_MAX: u64 = (1u64 << 46) - 1;
+
+/// `NanBoxable` for `i64`: values in `INLINE_I64_MIN..=INLINE_I64_MAX` are
+/// packed inline, shifted left by one to leave `SPILL_BIT` free; anything
+/// wider (including `i64::MIN`/`MAX`) is boxed on the heap and its pointer
+/// stored instead, flagged by `SPILL_BIT`. Because the heap case owns a
+/// `Box<i64>`, any `make_nanbox!` field using `i64` (or `isize`) must be
+/// declared `#[owned]`, the same as a `Box<T>` field, or the spilled
+/// allocation will leak.
+impl NanBoxable for i64 {
+    unsafe fn from_nan_box(n: NanBox) -> i64 {
+        if n.0 & SPILL_BIT == 0 {
+            // Drop the flag bit, then sign-extend the remaining 46 bits.
+            (((n.0 >> 1) << 18) as i64) >> 18
+        } else {
+            *Box::from_raw((n.0 & !SPILL_BIT) as *mut i64)
+        }
+    }
+
+    fn into_nan_box(self) -> NanBox {
+        if self >= INLINE_I64_MIN && self <= INLINE_I64_MAX {
+            NanBox(((self as u64) << 1) & PAYLOAD_MASK)
+        } else {
+            let ptr = Box::into_raw(Box::new(self)) as u64;
+            debug_assert!(ptr & !PAYLOAD_MASK == 0);
+            debug_assert!(ptr & SPILL_BIT == 0, "Box<i64> must be aligned enough to leave the spill flag bit free");
+            NanBox(ptr | SPILL_BIT)
+        }
+    }
+}
+
+impl NanBoxOwned for i64 {
+    unsafe fn reconstruct(value: NanBox) -> Self {
+        let payload = value.0 & PAYLOAD_MASK;
+        if payload & SPILL_BIT == 0 {
+            // No heap involved in the inline case, so `from_nan_box` is a
+            // plain bit decode either way.
+            Self::from_nan_box(NanBox(payload))
+        } else {
+            // Unlike `from_nan_box`, this must not free the `Box<i64>`:
+            // callers (`Clone`, `Debug`) use `reconstruct` to peek at a
+            // payload that `value` still owns, and rely on the backing
+            // allocation staying alive until the real `Drop` runs.
+            *((payload & !SPILL_BIT) as *const i64)
+        }
+    }
+
+    unsafe fn drop_payload(value: NanBox) {
+        // `from_nan_box`'s spilled branch both reads and frees the
+        // `Box<i64>`; `reconstruct` above deliberately doesn't, so this is
+        // the one place that actually reclaims it.
+        let _ = Self::unpack_nan_box(value);
+    }
+}
+
+/// `NanBoxable` for `u64`, mirroring the `i64` impl without the sign
+/// extension. See the `i64` impl for the spill scheme and the `#[owned]`
+/// requirement this carries over to `make_nanbox!` fields.
+impl NanBoxable for u64 {
+    unsafe fn from_nan_box(n: NanBox) -> u64 {
+        if n.0 & SPILL_BIT == 0 {
+            n.0 >> 1
+        } else {
+            *Box::from_raw((n.0 & !SPILL_BIT) as *mut u64)
+        }
+    }
+
+    fn into_nan_box(self) -> NanBox {
+        if self <= INLINE_U64_MAX {
+            NanBox((self << 1) & PAYLOAD_MASK)
+        } else {
+            let ptr = Box::into_raw(Box::new(self)) as u64;
+            debug_assert!(ptr & !PAYLOAD_MASK == 0);
+            debug_assert!(ptr & SPILL_BIT == 0, "Box<u64> must be aligned enough to leave the spill flag bit free");
+            NanBox(ptr | SPILL_BIT)
+        }
+    }
+}
+
+impl NanBoxOwned for u64 {
+    unsafe fn reconstruct(value: NanBox) -> Self {
+        let payload = value.0 & PAYLOAD_MASK;
+        if payload & SPILL_BIT == 0 {
+            Self::from_nan_box(NanBox(payload))
+        } else {
+            // See the `i64` impl: this must peek, not free.
+            *((payload & !SPILL_BIT) as *const u64)
+        }
+    }
+
+    unsafe fn drop_payload(value: NanBox) {
+        let _ = Self::unpack_nan_box(value);
+    }
+}
+
+macro_rules! impl_cast_64_via {
+    ($($typ: ident => $via: ident),+) => {
+        $(
+        impl NanBoxable for $typ {
+            unsafe fn from_nan_box(n: NanBox) -> $typ {
+                <$via as NanBoxable>::from_nan_box(n) as $typ
+            }
+
+            fn into_nan_box(self) -> NanBox {
+                (self as $via).into_nan_box()
+            }
+        }
+
+        impl NanBoxOwned for $typ {
+            unsafe fn reconstruct(value: NanBox) -> Self {
+                <$via as NanBoxOwned>::reconstruct(value) as $typ
+            }
+
+            unsafe fn drop_payload(value: NanBox) {
+                <$via as NanBoxOwned>::drop_payload(value)
+            }
+        }
+        )+
+    }
+}
+
+impl_cast_64_via! { isize => i64, usize => u64 }
+
+impl<T> NanBoxable for Box<T> {
+    unsafe fn from_nan_box(n: NanBox) -> Box<T> {
+        Box::from_raw(<*mut T as NanBoxable>::from_nan_box(n))
+    }
+
+    fn into_nan_box(self) -> NanBox {
+        Box::into_raw(self).into_nan_box()
+    }
+}
+
+impl<T> NanBoxable for ::std::rc::Rc<T> {
+    unsafe fn from_nan_box(n: NanBox) -> ::std::rc::Rc<T> {
+        ::std::rc::Rc::from_raw(<*const T as NanBoxable>::from_nan_box(n))
+    }
+
+    fn into_nan_box(self) -> NanBox {
+        ::std::rc::Rc::into_raw(self).into_nan_box()
+    }
+}
+
+impl<T> NanBoxable for ::std::sync::Arc<T> {
+    unsafe fn from_nan_box(n: NanBox) -> ::std::sync::Arc<T> {
+        ::std::sync::Arc::from_raw(<*const T as NanBoxable>::from_nan_box(n))
+    }
+
+    fn into_nan_box(self) -> NanBox {
+        ::std::sync::Arc::into_raw(self).into_nan_box()
+    }
+}
+
+/// A `NanBoxable` type whose payload is not a plain copy of its bits but an
+/// owning handle (a `Box`, an `Rc`, ...) to memory reachable through the
+/// payload.
+///
+/// `make_nanbox!` uses this trait to generate a `Drop` impl that reclaims the
+/// owned value instead of leaking it, and a `Clone` impl that clones the
+/// pointee rather than aliasing the raw bits.
+pub trait NanBoxOwned: NanBoxable {
+    /// Rebuilds `Self` from `value` without consuming it. For `Box`/`Rc`/`Arc`
+    /// this takes back ownership of whatever the payload points to; for
+    /// spilled `i64`/`u64` it returns a copy of the value and leaves the heap
+    /// allocation owned by `value` (see `drop_payload`).
+    ///
+    /// # Safety
+    /// `value` must hold a live payload of type `Self` that was produced by
+    /// `into_nan_box`/`forget_into_payload`. If the result owns the payload
+    /// while `value` still does, it must not be dropped (the generated
+    /// `Clone` and `Debug` wrap it in `ManuallyDrop`), or ownership would be
+    /// duplicated.
+    unsafe fn reconstruct(value: NanBox) -> Self;
+
+    /// The inverse of `reconstruct`: packs `self` back into a `NanBox`
+    /// tagged with `tag`, without running `Self`'s destructor, handing
+    /// ownership to the box.
+    fn forget_into_payload(self, tag: u8) -> NanBox {
+        self.pack_nan_box(tag)
+    }
+
+    /// Reclaims whatever `value`'s payload owns, without needing a `Self`
+    /// to hand back.
+    ///
+    /// For `Box`/`Rc`/`Arc`, `reconstruct` already hands back the owning
+    /// pointer without freeing anything itself, so the default of
+    /// reconstructing and dropping it is enough. Spilled primitives
+    /// (`i64`/`u64`/...) override this: their `reconstruct` is a
+    /// non-owning peek, and the plain value it returns has no destructor
+    /// of its own to defer the free to.
+    ///
+    /// # Safety
+    /// Same requirement as `reconstruct`: `value` must hold a live payload
+    /// that hasn't already been reconstructed or reclaimed elsewhere.
+    unsafe fn drop_payload(value: NanBox) {
+        drop(Self::reconstruct(value));
+    }
+}
+
+impl<T> NanBoxOwned for Box<T> {
+    unsafe fn reconstruct(value: NanBox) -> Self {
+        Self::unpack_nan_box(value)
+    }
+}
+
+impl<T> NanBoxOwned for ::std::rc::Rc<T> {
+    unsafe fn reconstruct(value: NanBox) -> Self {
+        Self::unpack_nan_box(value)
+    }
+}
+
+impl<T> NanBoxOwned for ::std::sync::Arc<T> {
+    unsafe fn reconstruct(value: NanBox) -> Self {
+        Self::unpack_nan_box(value)
+    }
+}
+
 impl NanBox {
     pub unsafe fn new<T>(tag: u8, value: T) -> NanBox
         where T: NanBoxable
@@ -115,21 +355,169 @@ impl NanBox {
     }
 }
 
+/// Maps the bits of a float to an `i64` such that comparing the results as
+/// integers gives IEEE 754 total ordering: `-0.0 < +0.0`, every negative
+/// value sorts below every positive one, and all `NaN`s sort consistently
+/// at the ends (ordered amongst themselves by payload/sign).
+fn total_order_key(bits: i64) -> i64 {
+    bits ^ (((bits >> 63) as u64) >> 1) as i64
+}
+
+/// A `NanBox` wrapper with a semantic, total `Ord`/`Hash` instead of
+/// `NanBox`'s derived comparison of the raw bit pattern.
+///
+/// `NanBox` itself keeps comparing its bits directly (it has no `Hash` impl),
+/// which is wrong for floats (every negative value, `-0.0` included, sorts
+/// above every positive one, `NaN`s land in an arbitrary place) and
+/// meaningless across tags (a float payload can sort
+/// ahead of or behind a pointer payload depending on their raw bits).
+/// `OrderedNanBox` instead orders by `tag()` first, then within the float
+/// tag (0) uses IEEE total ordering; wrap a value in it to get comparisons
+/// and hashes that make semantic sense, while code relying on `NanBox`'s
+/// bitwise behaviour is unaffected.
+#[derive(Copy, Clone, Debug)]
+pub struct OrderedNanBox(pub NanBox);
+
+impl OrderedNanBox {
+    fn cmp_key(self) -> (u32, i64) {
+        let tag = self.0.tag();
+        let payload = if tag == 0 {
+            total_order_key(unsafe { self.0.unpack::<f64>() }.to_bits() as i64)
+        } else {
+            self.0 .0 as i64
+        };
+        (tag, payload)
+    }
+}
+
+impl PartialEq for OrderedNanBox {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp_key() == other.cmp_key()
+    }
+}
+
+impl Eq for OrderedNanBox {}
+
+impl PartialOrd for OrderedNanBox {
+    fn partial_cmp(&self, other: &Self) -> Option<::std::cmp::Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for OrderedNanBox {
+    fn cmp(&self, other: &Self) -> ::std::cmp::Ordering {
+        self.cmp_key().cmp(&other.cmp_key())
+    }
+}
+
+impl ::std::hash::Hash for OrderedNanBox {
+    fn hash<H: ::std::hash::Hasher>(&self, state: &mut H) {
+        self.cmp_key().hash(state)
+    }
+}
+
+#[doc(hidden)]
+#[macro_export]
+macro_rules! __nanbox_owned_drop {
+    (owned, $value: expr, $typ: ty) => {{
+        <$typ as $crate::NanBoxOwned>::drop_payload($value);
+    }};
+    ($other: ident, $value: expr, $typ: ty) => {{}};
+}
+
+#[doc(hidden)]
+#[macro_export]
+macro_rules! __nanbox_owned_clone {
+    (owned, $value: expr, $tag: expr, $typ: ty) => {{
+        let original = ::std::mem::ManuallyDrop::new(<$typ as $crate::NanBoxOwned>::reconstruct($value));
+        let cloned: $typ = (*original).clone();
+        <$typ as $crate::NanBoxOwned>::forget_into_payload(cloned, $tag)
+    }};
+    ($other: ident, $value: expr, $tag: expr, $typ: ty) => {{ $value }};
+}
+
+#[doc(hidden)]
+#[macro_export]
+macro_rules! __nanbox_debug_payload {
+    ([owned] $value: expr, $typ: ty) => {{
+        // `reconstruct` peeks without freeing, and `ManuallyDrop` keeps us
+        // from running the payload's destructor here either, so `self`
+        // keeps owning it.
+        let payload = ::std::mem::ManuallyDrop::new(<$typ as $crate::NanBoxOwned>::reconstruct($value));
+        format!("{:?}", &*payload)
+    }};
+    ([$($other: ident)?] $value: expr, $typ: ty) => {{
+        // Non-owned payloads never free anything on unpack, so plain
+        // `unpack_nan_box` is already a safe peek.
+        let payload = ::std::mem::ManuallyDrop::new(<$typ as $crate::NanBoxable>::unpack_nan_box($value));
+        format!("{:?}", &*payload)
+    }};
+}
+
+// `make_nanbox!` applies the caller's `$(#[$meta])*` to the generated
+// `$enum_name` as-is, but the generated `$name` struct already gets its own
+// hand-written `Debug`/`Clone` impls, so re-deriving either of those on the
+// struct would conflict. `$meta` is captured token-by-token (`# $meta:tt`
+// rather than `$meta:meta`) so that it stays transparent and a `derive(...)`
+// attribute among them can be pattern-matched again by these two helpers,
+// which strip just `Debug`/`Clone` out of it and leave every other derive
+// (and every non-derive attribute) on the struct untouched.
+#[doc(hidden)]
+#[macro_export]
+macro_rules! __nanbox_struct_attrs {
+    (() ($($kept: tt)*) $name: ident { $($typ: ty),* }) => {
+        $($kept)*
+        pub struct $name {
+            _marker: ::std::marker::PhantomData<($($typ),*)>,
+            value: $crate::NanBox,
+        }
+    };
+    ((# [derive($($trait: ident),* $(,)?)] $(# $rest: tt)*) ($($kept: tt)*) $name: ident { $($typ: ty),* }) => {
+        $crate::__nanbox_filter_derive! {
+            ($($trait),*) () ($(# $rest)*) ($($kept)*) $name { $($typ),* }
+        }
+    };
+    ((# $other: tt $(# $rest: tt)*) ($($kept: tt)*) $name: ident { $($typ: ty),* }) => {
+        $crate::__nanbox_struct_attrs! {
+            ($(# $rest)*) ($($kept)* # $other) $name { $($typ),* }
+        }
+    };
+}
+
+#[doc(hidden)]
+#[macro_export]
+macro_rules! __nanbox_filter_derive {
+    ((Debug $(, $rest: ident)*) ($($acc: ident),*) ($($tail: tt)*) ($($kept: tt)*) $name: ident { $($typ: ty),* }) => {
+        $crate::__nanbox_filter_derive! { ($($rest),*) ($($acc),*) ($($tail)*) ($($kept)*) $name { $($typ),* } }
+    };
+    ((Clone $(, $rest: ident)*) ($($acc: ident),*) ($($tail: tt)*) ($($kept: tt)*) $name: ident { $($typ: ty),* }) => {
+        $crate::__nanbox_filter_derive! { ($($rest),*) ($($acc),*) ($($tail)*) ($($kept)*) $name { $($typ),* } }
+    };
+    (($head: ident $(, $rest: ident)*) ($($acc: ident),*) ($($tail: tt)*) ($($kept: tt)*) $name: ident { $($typ: ty),* }) => {
+        $crate::__nanbox_filter_derive! { ($($rest),*) ($($acc,)* $head) ($($tail)*) ($($kept)*) $name { $($typ),* } }
+    };
+    (() () ($($tail: tt)*) ($($kept: tt)*) $name: ident { $($typ: ty),* }) => {
+        $crate::__nanbox_struct_attrs! { ($($tail)*) ($($kept)*) $name { $($typ),* } }
+    };
+    (() ($($acc: ident),+) ($($tail: tt)*) ($($kept: tt)*) $name: ident { $($typ: ty),* }) => {
+        $crate::__nanbox_struct_attrs! { ($($tail)*) ($($kept)* #[derive($($acc),+)]) $name { $($typ),* } }
+    };
+}
+
+#[macro_export]
 macro_rules! make_nanbox {
     (
-        $(#[$meta:meta])*
+        $(# $meta: tt)*
         pub enum $name: ident, $enum_name: ident {
-            $($field: ident ($typ: ty)),*
+            $($(#[$fattr: ident])? $field: ident ($typ: ty)),*
         }
     ) => {
-        
-        $(#[$meta])*
-        pub struct $name {
-            _marker: ::std::marker::PhantomData<($($typ),*)>,
-            value: $crate::NanBox,
+
+        $crate::__nanbox_struct_attrs! {
+            ($(# $meta)*) () $name { $($typ),* }
         }
 
-        $(#[$meta])*
+        $(# $meta)*
         pub enum $enum_name {
             $(
                 $field($typ),
@@ -166,19 +554,201 @@ macro_rules! make_nanbox {
         impl $name {
             pub fn into_variant(self) -> $enum_name {
                 #[allow(unused_assignments)]
+                unsafe {
+                    let value = self.value;
+                    ::std::mem::forget(self);
+                    let mut expected_tag = 0;
+                    $(
+                        if expected_tag == value.tag() {
+                            return $enum_name::$field(value.unpack());
+                        }
+                        expected_tag += 1;
+                    )*
+                    debug_assert!(false, "Unexpected tag {}", value.tag());
+                    unreachable!()
+                }
+            }
+        }
+
+        impl ::std::ops::Drop for $name {
+            fn drop(&mut self) {
+                #[allow(unused_assignments, unused_unsafe)]
+                unsafe {
+                    let mut expected_tag = 0;
+                    $(
+                        if expected_tag == self.value.tag() {
+                            $(
+                                $crate::__nanbox_owned_drop!($fattr, self.value, $typ);
+                            )?
+                        }
+                        expected_tag += 1;
+                    )*
+                }
+            }
+        }
+
+        impl Clone for $name {
+            fn clone(&self) -> Self {
+                #[allow(unused_assignments, unused_unsafe, unused_mut)]
+                unsafe {
+                    let mut expected_tag = 0;
+                    let mut value = self.value;
+                    $(
+                        if expected_tag == self.value.tag() {
+                            $(
+                                value = $crate::__nanbox_owned_clone!($fattr, self.value, expected_tag as u8, $typ);
+                            )?
+                        }
+                        expected_tag += 1;
+                    )*
+                    $name {
+                        _marker: ::std::marker::PhantomData,
+                        value: value,
+                    }
+                }
+            }
+        }
+
+        impl ::std::fmt::Debug for $name {
+            fn fmt(&self, f: &mut ::std::fmt::Formatter) -> ::std::fmt::Result {
+                #[allow(unused_assignments, unused_unsafe)]
                 unsafe {
                     let mut expected_tag = 0;
                     $(
                         if expected_tag == self.value.tag() {
-                            return $enum_name::$field(self.value.unpack());
+                            let payload = $crate::__nanbox_debug_payload!([$($fattr)?] self.value, $typ);
+                            return write!(f,
+                                          "{}::{}({})",
+                                          stringify!($enum_name),
+                                          stringify!($field),
+                                          payload);
                         }
                         expected_tag += 1;
                     )*
-                    debug_assert!(false, "Unexpected tag {}", self.value.tag());
                     unreachable!()
                 }
             }
         }
+
+    }
+}
+
+/// Like `make_nanbox!`, but also generates `serde::Serialize`/`Deserialize`
+/// impls for `$name` that (de)serialize it as an externally-tagged
+/// `$enum_name`.
+///
+/// This is a separate, opt-in macro rather than something `make_nanbox!`
+/// always emits under `feature = "serde"`, because not every payload type is
+/// `Serialize`/`Deserialize` (a raw pointer variant, for instance, never is)
+/// — invoke this only for unions whose fields all support serde.
+#[cfg(feature = "serde")]
+#[macro_export]
+macro_rules! make_nanbox_serde {
+    (
+        $(# $meta: tt)*
+        pub enum $name: ident, $enum_name: ident {
+            $($(#[$fattr: ident])? $field: ident ($typ: ty)),*
+        }
+    ) => {
+        $crate::make_nanbox! {
+            $(# $meta)*
+            pub enum $name, $enum_name {
+                $($(#[$fattr])? $field($typ)),*
+            }
+        }
+
+        impl ::serde::Serialize for $name {
+            fn serialize<S>(&self, serializer: S) -> ::std::result::Result<S::Ok, S::Error>
+                where S: ::serde::Serializer
+            {
+                #[allow(unused_assignments, unused_unsafe)]
+                unsafe {
+                    let mut expected_tag = 0;
+                    $(
+                        if expected_tag == self.value.tag() {
+                            let payload = ::std::mem::ManuallyDrop::new(
+                                <$typ as $crate::NanBoxable>::unpack_nan_box(self.value));
+                            return serializer.serialize_newtype_variant(
+                                stringify!($enum_name),
+                                expected_tag,
+                                stringify!($field),
+                                &*payload,
+                            );
+                        }
+                        expected_tag += 1;
+                    )*
+                    unreachable!()
+                }
+            }
+        }
+
+        impl<'de> ::serde::Deserialize<'de> for $name {
+            fn deserialize<D>(deserializer: D) -> ::std::result::Result<Self, D::Error>
+                where D: ::serde::Deserializer<'de>
+            {
+                use ::serde::de::{Error, EnumAccess, VariantAccess, Visitor};
+
+                #[allow(non_camel_case_types)]
+                enum Field {
+                    $($field,)*
+                }
+
+                impl<'de> ::serde::Deserialize<'de> for Field {
+                    fn deserialize<D>(deserializer: D) -> ::std::result::Result<Field, D::Error>
+                        where D: ::serde::Deserializer<'de>
+                    {
+                        struct FieldVisitor;
+
+                        impl<'de> Visitor<'de> for FieldVisitor {
+                            type Value = Field;
+
+                            fn expecting(&self, f: &mut ::std::fmt::Formatter) -> ::std::fmt::Result {
+                                f.write_str("variant identifier")
+                            }
+
+                            fn visit_str<E>(self, v: &str) -> ::std::result::Result<Field, E>
+                                where E: Error
+                            {
+                                match v {
+                                    $(stringify!($field) => Ok(Field::$field),)*
+                                    _ => Err(Error::unknown_variant(v, VARIANTS)),
+                                }
+                            }
+                        }
+
+                        deserializer.deserialize_identifier(FieldVisitor)
+                    }
+                }
+
+                struct ValueVisitor;
+
+                impl<'de> Visitor<'de> for ValueVisitor {
+                    type Value = $enum_name;
+
+                    fn expecting(&self, f: &mut ::std::fmt::Formatter) -> ::std::fmt::Result {
+                        write!(f, "enum {}", stringify!($enum_name))
+                    }
+
+                    fn visit_enum<A>(self, data: A) -> ::std::result::Result<$enum_name, A::Error>
+                        where A: EnumAccess<'de>
+                    {
+                        match data.variant()? {
+                            $(
+                                (Field::$field, variant) => {
+                                    variant.newtype_variant().map($enum_name::$field)
+                                }
+                            )*
+                        }
+                    }
+                }
+
+                const VARIANTS: &'static [&'static str] = &[ $(stringify!($field)),* ];
+
+                deserializer
+                    .deserialize_enum(stringify!($enum_name), VARIANTS, ValueVisitor)
+                    .map($name::from)
+            }
+        }
     }
 }
 
@@ -186,15 +756,23 @@ macro_rules! make_nanbox {
 #[macro_use]
 extern crate quickcheck;
 
+#[cfg(all(test, feature = "serde"))]
+extern crate serde_test;
+
 #[cfg(test)]
 mod tests {
     use super::*;
 
+    use std::cell::Cell;
     use std::f64;
     use std::fmt;
+    use std::rc::Rc;
 
     use quickcheck::TestResult;
 
+    #[cfg(feature = "serde")]
+    use serde_test::{assert_tokens, Token};
+
     fn test_eq<T>(l: T, r: T) -> TestResult
         where T: PartialEq + fmt::Debug
     {
@@ -220,6 +798,44 @@ mod tests {
                 TestResult::from_bool(NanBox::new(tag, v).tag() == tag as u32)
             }
         }
+
+        fn nanbox_i64_roundtrips_inline_and_spilled(v: i64) -> TestResult {
+            unsafe {
+                test_eq(NanBox::new(1, v).unpack(), v)
+            }
+        }
+
+        fn nanbox_u64_roundtrips_inline_and_spilled(v: u64) -> TestResult {
+            unsafe {
+                test_eq(NanBox::new(1, v).unpack(), v)
+            }
+        }
+    }
+
+    #[test]
+    fn i64_min_and_max_spill_to_the_heap() {
+        unsafe {
+            assert_eq!(NanBox::new(0, i64::min_value()).unpack::<i64>(), i64::min_value());
+            assert_eq!(NanBox::new(0, i64::max_value()).unpack::<i64>(), i64::max_value());
+        }
+    }
+
+    #[test]
+    fn i64_values_straddling_the_inline_boundary_round_trip() {
+        for v in &[INLINE_I64_MIN - 1, INLINE_I64_MIN, INLINE_I64_MAX, INLINE_I64_MAX + 1] {
+            unsafe {
+                assert_eq!(NanBox::new(0, *v).unpack::<i64>(), *v);
+            }
+        }
+    }
+
+    #[test]
+    fn u64_values_straddling_the_inline_boundary_round_trip() {
+        for v in &[INLINE_U64_MAX, INLINE_U64_MAX + 1, u64::max_value()] {
+            unsafe {
+                assert_eq!(NanBox::new(0, *v).unpack::<u64>(), *v);
+            }
+        }
     }
 
     make_nanbox!{
@@ -239,6 +855,36 @@ mod tests {
         assert_eq!(Value::from(3.14).into_variant(), Variant::Float(3.14));
     }
 
+    #[test]
+    fn debug_prints_the_live_variant() {
+        assert_eq!(format!("{:?}", Value::from(123)), "Variant::Int(123)");
+        assert_eq!(format!("{:?}", Value::from(3.14)), "Variant::Float(3.14)");
+    }
+
+    // `Value` above has a raw pointer field, which can never be `Serialize`,
+    // so it deliberately stays on plain `make_nanbox!`. `SerdeValue` opts
+    // into `make_nanbox_serde!` instead, since all of its payloads support
+    // serde.
+    #[cfg(feature = "serde")]
+    make_nanbox_serde!{
+        #[derive(Debug, PartialEq)]
+        pub enum SerdeValue, SerdeVariant {
+            Float(f64),
+            Int(i32)
+        }
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn serde_round_trips_through_the_variant_name() {
+        assert_tokens(&SerdeValue::from(123),
+                      &[Token::NewtypeVariant { name: "SerdeVariant", variant: "Int" },
+                        Token::I32(123)]);
+        assert_tokens(&SerdeValue::from(3.14),
+                      &[Token::NewtypeVariant { name: "SerdeVariant", variant: "Float" },
+                        Token::F64(3.14)]);
+    }
+
     #[test]
     fn nan_box_nan() {
         match Value::from(f64::NAN).into_variant() {
@@ -252,4 +898,148 @@ mod tests {
     fn invalid_pointer() {
         ((1u64 << 48) as *const ()).into_nan_box();
     }
+
+    #[test]
+    fn ordered_nan_box_distinguishes_zero_sign() {
+        let neg_zero = OrderedNanBox(unsafe { NanBox::new(0, -0.0) });
+        let pos_zero = OrderedNanBox(unsafe { NanBox::new(0, 0.0) });
+        assert!(neg_zero < pos_zero);
+        assert_ne!(neg_zero, pos_zero);
+    }
+
+    #[test]
+    fn ordered_nan_box_orders_by_tag_before_payload() {
+        let float = OrderedNanBox(unsafe { NanBox::new(0, 1_000_000.0) });
+        let int = OrderedNanBox(unsafe { NanBox::new(1, 1i32) });
+        assert!(float < int);
+    }
+
+    #[test]
+    fn ordered_nan_box_nan_is_reflexive() {
+        let nan = OrderedNanBox(unsafe { NanBox::new(0, f64::NAN) });
+        assert_eq!(nan, nan);
+        assert_eq!(nan.cmp(&nan), ::std::cmp::Ordering::Equal);
+    }
+
+    quickcheck!{
+        fn ordered_nan_box_cmp_agrees_with_eq(l: f64, r: f64) -> TestResult {
+            unsafe {
+                let l_box = OrderedNanBox(NanBox::new(0, l));
+                let r_box = OrderedNanBox(NanBox::new(0, r));
+                let cmp_equal = l_box.cmp(&r_box) == ::std::cmp::Ordering::Equal;
+                TestResult::from_bool(cmp_equal == (l_box == r_box))
+            }
+        }
+
+        fn ordered_nan_box_total_order_is_antisymmetric(l: f64, r: f64) -> TestResult {
+            unsafe {
+                let l_box = OrderedNanBox(NanBox::new(0, l));
+                let r_box = OrderedNanBox(NanBox::new(0, r));
+                TestResult::from_bool(l_box.cmp(&r_box) == r_box.cmp(&l_box).reverse())
+            }
+        }
+    }
+
+    #[derive(Debug)]
+    struct DropTracker(Rc<Cell<u32>>);
+
+    impl Clone for DropTracker {
+        fn clone(&self) -> Self {
+            DropTracker(self.0.clone())
+        }
+    }
+
+    impl Drop for DropTracker {
+        fn drop(&mut self) {
+            self.0.set(self.0.get() + 1);
+        }
+    }
+
+    make_nanbox!{
+        pub enum Owning, OwningVariant {
+            Int(i32),
+            #[owned]
+            Boxed(Box<DropTracker>)
+        }
+    }
+
+    #[test]
+    fn owned_variant_is_dropped() {
+        let drops = Rc::new(Cell::new(0));
+        {
+            let _owning = Owning::from(Box::new(DropTracker(drops.clone())));
+        }
+        assert_eq!(drops.get(), 1);
+    }
+
+    #[test]
+    fn owned_variant_clone_drops_independently() {
+        let drops = Rc::new(Cell::new(0));
+        let owning = Owning::from(Box::new(DropTracker(drops.clone())));
+        let cloned = owning.clone();
+
+        drop(owning);
+        assert_eq!(drops.get(), 1);
+
+        drop(cloned);
+        assert_eq!(drops.get(), 2);
+    }
+
+    #[test]
+    fn non_owned_variant_is_copied_on_clone() {
+        let owning = Owning::from(123);
+        match owning.clone().into_variant() {
+            OwningVariant::Int(v) => assert_eq!(v, 123),
+            OwningVariant::Boxed(_) => panic!("expected Int"),
+        }
+    }
+
+    #[test]
+    fn debug_peeks_owned_payload_without_dropping_it() {
+        let drops = Rc::new(Cell::new(0));
+        let owning = Owning::from(Box::new(DropTracker(drops.clone())));
+
+        assert_eq!(format!("{:?}", owning),
+                   "OwningVariant::Boxed(DropTracker(Cell { value: 0 }))");
+        assert_eq!(drops.get(), 0);
+
+        drop(owning);
+        assert_eq!(drops.get(), 1);
+    }
+
+    make_nanbox!{
+        #[derive(Debug, PartialEq)]
+        pub enum Wide, WideVariant {
+            Int(i32),
+            #[owned]
+            Big(i64)
+        }
+    }
+
+    #[test]
+    fn spilled_i64_field_round_trips_through_the_macro() {
+        assert_eq!(Wide::from(i64::max_value()).into_variant(), WideVariant::Big(i64::max_value()));
+        assert_eq!(Wide::from(1i64).into_variant(), WideVariant::Big(1));
+    }
+
+    #[test]
+    fn cloning_a_spilled_owned_int_leaves_the_original_intact() {
+        let big = Wide::from(i64::max_value());
+        let cloned = big.clone();
+
+        // Both copies must decode correctly and independently; if `clone`
+        // had freed the original's spilled `Box<i64>`, one of these reads
+        // would be a use-after-free.
+        assert_eq!(cloned.into_variant(), WideVariant::Big(i64::max_value()));
+        assert_eq!(big.into_variant(), WideVariant::Big(i64::max_value()));
+    }
+
+    #[test]
+    fn debug_peeks_spilled_owned_int_without_freeing_it() {
+        let big = Wide::from(i64::max_value());
+
+        assert_eq!(format!("{:?}", big), "WideVariant::Big(9223372036854775807)");
+        // The allocation must still be alive after formatting.
+        assert_eq!(big.into_variant(), WideVariant::Big(i64::max_value()));
+    }
 }
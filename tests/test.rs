@@ -2,10 +2,25 @@
 extern crate nanbox;
 
 make_nanbox!{
-    pub unsafe enum Value, Variant {
+    #[derive(Debug, PartialEq)]
+    pub enum Value, Variant {
         Float(f64),
         Byte(u8),
         Int(i32),
         Pointer(*mut Value)
     }
 }
+
+#[test]
+fn downstream_consumer_round_trips_through_the_exported_macro() {
+    assert_eq!(Value::from(123).into_variant(), Variant::Int(123));
+    assert_eq!(Value::from(1u8).into_variant(), Variant::Byte(1));
+    assert_eq!(Value::from(3.14).into_variant(), Variant::Float(3.14));
+
+    let mut value = 0;
+    let ptr = Value::from(&mut value as *mut i32 as *mut Value);
+    match ptr.into_variant() {
+        Variant::Pointer(p) => assert_eq!(p, &mut value as *mut i32 as *mut Value),
+        other => panic!("expected Pointer, got {:?}", other),
+    }
+}